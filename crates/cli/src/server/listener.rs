@@ -0,0 +1,146 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Binding the main listener, over either TCP or (on Unix) a domain socket.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    pin::Pin,
+};
+
+use anyhow::Context;
+use futures::{stream::TryStreamExt, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::TcpListenerStream;
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tracing::info;
+
+/// A boxed, type-erased connection, so that TCP and Unix sockets -- optionally
+/// wrapped in TLS -- can be driven through the same hyper server.
+pub(super) trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+pub(super) type BoxedIncoming =
+    Pin<Box<dyn Stream<Item = io::Result<Box<dyn Connection>>> + Send>>;
+
+/// Permissions applied to a freshly-bound Unix domain socket, so that only
+/// processes in the same group (typically a local reverse proxy) can connect.
+#[cfg(unix)]
+const UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Either end of the socket we accept connections on.
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix { listener: UnixListener, path: PathBuf },
+}
+
+/// Bind the configured listener address, which is either a `host:port` pair
+/// or, when prefixed with `unix:`, a filesystem path to bind a Unix domain
+/// socket on. The `unix:` form is only available on Unix targets.
+///
+/// `unix_socket_mode` is the octal string from `config.http.unix_socket_mode`,
+/// applied to a freshly-bound socket; defaults to [`UNIX_SOCKET_MODE`].
+pub(super) fn bind_listener(
+    address: &str,
+    unix_socket_mode: Option<&str>,
+) -> anyhow::Result<Listener> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let mode = match unix_socket_mode {
+                Some(mode) => u32::from_str_radix(mode, 8)
+                    .with_context(|| format!("invalid http.unix_socket_mode {mode:?}"))?,
+                None => UNIX_SOCKET_MODE,
+            };
+
+            let path = PathBuf::from(path);
+
+            // Remove any stale socket file left over from a previous run
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("could not remove stale unix socket {}", path.display())
+                    })
+                }
+            }
+
+            // Tighten the umask around the bind call so the socket is
+            // created with exactly `mode` from the start, rather than
+            // briefly existing at the default (umask-derived) permissions
+            // before a follow-up chmod narrows them.
+            let previous_umask = unsafe { libc::umask(0o777 & !mode) };
+            let listener = UnixListener::bind(&path)
+                .with_context(|| format!("could not bind unix socket at {}", path.display()));
+            unsafe { libc::umask(previous_umask) };
+            let listener = listener?;
+
+            Ok(Listener::Unix { listener, path })
+        }
+        #[cfg(not(unix))]
+        {
+            anyhow::bail!(
+                "unix socket listener `{}` requested, but this build only supports TCP listeners",
+                address
+            );
+        }
+    } else {
+        let addr: SocketAddr = address
+            .parse()
+            .context("could not parse listener address")?;
+        let listener = TcpListener::bind(addr).context("could not bind address")?;
+        Ok(Listener::Tcp(listener))
+    }
+}
+
+impl Listener {
+    /// The filesystem path backing this listener, if it's a Unix socket, so
+    /// the caller can clean it up on exit.
+    pub(super) fn socket_path(&self) -> Option<PathBuf> {
+        match self {
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Some(path.clone()),
+            Listener::Tcp(_) => None,
+        }
+    }
+
+    /// Turn this listener into a single stream of accepted connections,
+    /// erasing whether it came from a TCP or a Unix socket.
+    pub(super) fn into_incoming(self) -> anyhow::Result<BoxedIncoming> {
+        match self {
+            Listener::Tcp(listener) => {
+                info!("Listening on http://{}", listener.local_addr().unwrap());
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let stream = TcpListenerStream::new(listener)
+                    .map_ok(|stream| Box::new(stream) as Box<dyn Connection>);
+                Ok(Box::pin(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, path } => {
+                info!("Listening on unix socket {}", path.display());
+                let stream = UnixListenerStream::new(listener)
+                    .map_ok(|stream| Box::new(stream) as Box<dyn Connection>);
+                Ok(Box::pin(stream))
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Request metrics for the main listener, and the routes served by the
+//! separate admin/metrics listener.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Instant,
+};
+
+use arc_swap::ArcSwap;
+use mas_email::MailTransport;
+use opentelemetry::{
+    metrics::{Counter, Histogram, Meter, UpDownCounter},
+    KeyValue,
+};
+use prometheus::Encoder;
+use sqlx::Executor;
+use tower::Service;
+use warp::Filter;
+
+/// Default address the admin/metrics listener binds to when the config
+/// doesn't specify one: loopback-only, since it's meant to be scraped
+/// locally rather than exposed alongside the main listener.
+pub(super) const DEFAULT_ADMIN_ADDRESS: &str = "127.0.0.1:8081";
+
+/// Request-level metrics recorded for every response served by the main
+/// listener, keyed by route, method and status code.
+pub(super) struct HttpMetrics {
+    requests: Counter<u64>,
+    in_flight: UpDownCounter<i64>,
+    latency: Histogram<f64>,
+
+    /// Plain counter mirroring `in_flight`, so the graceful-shutdown drain
+    /// can log how many requests were abandoned without having to read an
+    /// OpenTelemetry instrument back out.
+    in_flight_count: Arc<AtomicI64>,
+}
+
+impl HttpMetrics {
+    pub(super) fn new(meter: &Meter) -> Self {
+        Self {
+            requests: meter
+                .u64_counter("http.server.requests")
+                .with_description("Number of HTTP requests served")
+                .init(),
+            in_flight: meter
+                .i64_up_down_counter("http.server.active_requests")
+                .with_description("Number of HTTP requests currently being served")
+                .init(),
+            latency: meter
+                .f64_histogram("http.server.duration")
+                .with_description("Duration of HTTP requests, in seconds")
+                .init(),
+            in_flight_count: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    /// Number of requests currently being served.
+    pub(super) fn in_flight(&self) -> i64 {
+        self.in_flight_count.load(Ordering::Relaxed)
+    }
+}
+
+/// A [`tower::Layer`] that records [`HttpMetrics`] around the inner service.
+#[derive(Clone)]
+pub(super) struct MetricsLayer {
+    pub(super) metrics: Arc<HttpMetrics>,
+}
+
+impl<S> tower::Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct MetricsService<S> {
+    inner: S,
+    metrics: Arc<HttpMetrics>,
+}
+
+/// Turn a request path into a label with bounded cardinality for the
+/// `http.route` metric.
+///
+/// `mas_handlers::root()` doesn't expose the matched route's path spec to
+/// the `tower::Service` wrapping it, so the literal URI path can't be
+/// swapped for a true route template here. Instead, collapse segments that
+/// look like a resource id (at least 8 alphanumerics or hyphens, containing
+/// at least one digit, as in a ULID/UUID) into a fixed placeholder, so e.g.
+/// `/users/42ab9f01` and `/users/77cd3e02` share one series instead of one
+/// per user. Shorter segments, like `/users/42`, are kept as they are.
+fn route_label(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if looks_like_resource_id(segment) {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn looks_like_resource_id(segment: &str) -> bool {
+    segment.len() >= 8
+        && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && segment.chars().any(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod route_label_tests {
+    use super::{looks_like_resource_id, route_label};
+
+    #[test]
+    fn collapses_id_shaped_segments() {
+        assert_eq!(route_label("/users/42ab9f01"), "/users/:id");
+        assert_eq!(route_label("/sessions/0123-4567-89ab"), "/sessions/:id");
+    }
+
+    #[test]
+    fn leaves_word_segments_alone() {
+        assert_eq!(route_label("/oauth2/authorize"), "/oauth2/authorize");
+        assert_eq!(route_label("/.well-known/openid-configuration"), "/.well-known/openid-configuration");
+    }
+
+    #[test]
+    fn does_not_treat_short_or_alphabetic_segments_as_ids() {
+        assert!(!looks_like_resource_id("ab12"));
+        assert!(!looks_like_resource_id("alphabetic"));
+        assert!(looks_like_resource_id("42ab9f01"));
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<hyper::Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<hyper::Request<ReqBody>, Response = hyper::Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<ReqBody>) -> Self::Future {
+        let metrics = self.metrics.clone();
+        let mut labels = vec![
+            KeyValue::new("http.route", route_label(req.uri().path())),
+            KeyValue::new("http.method", req.method().to_string()),
+        ];
+        let start = Instant::now();
+
+        metrics.in_flight.add(1, &labels);
+        metrics.in_flight_count.fetch_add(1, Ordering::Relaxed);
+
+        let mut inner = self.inner.clone();
+        Box::pin(async move {
+            let result = inner.call(req).await;
+            metrics.in_flight.add(-1, &labels);
+            metrics.in_flight_count.fetch_sub(1, Ordering::Relaxed);
+
+            if let Ok(response) = &result {
+                if response.status() == hyper::StatusCode::NOT_FOUND {
+                    // `route_label` only collapses segments that look like a
+                    // resource id; a path that doesn't match any route but
+                    // also doesn't look like one (typos, scanners probing
+                    // for endpoints) would otherwise pass through as its own
+                    // unbounded label. Since we now know the request didn't
+                    // match anything, bucket it instead of guessing from its
+                    // shape.
+                    labels[0] = KeyValue::new("http.route", "unmatched");
+                }
+                labels.push(KeyValue::new(
+                    "http.status_code",
+                    i64::from(response.status().as_u16()),
+                ));
+            }
+
+            metrics.requests.add(1, &labels);
+            metrics.latency.record(start.elapsed().as_secs_f64(), &labels);
+
+            result
+        })
+    }
+}
+
+/// Build the routes served by the admin/metrics listener: `/healthz`
+/// (checks the database pool and mail transport are reachable), `/readyz`,
+/// and a Prometheus-format `/metrics`.
+pub(super) fn admin_routes(
+    pool: sqlx::PgPool,
+    mail_transport: Arc<ArcSwap<MailTransport>>,
+    registry: prometheus::Registry,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = std::convert::Infallible> + Clone {
+    let healthz = warp::path("healthz")
+        .and_then(move || {
+            let pool = pool.clone();
+            let mail_transport = mail_transport.clone();
+            async move {
+                let db_ok = sqlx::query("SELECT 1").execute(&pool).await.is_ok();
+                // Load a fresh snapshot on every check, so a config reload
+                // that rebuilds the mail transport is reflected here too.
+                let mail_ok = mail_transport.load().test_connection().await.is_ok();
+
+                let status = if db_ok && mail_ok {
+                    warp::http::StatusCode::OK
+                } else {
+                    warp::http::StatusCode::SERVICE_UNAVAILABLE
+                };
+
+                Ok::<_, std::convert::Infallible>(warp::reply::with_status(
+                    if db_ok && mail_ok { "ok" } else { "unhealthy" },
+                    status,
+                ))
+            }
+        })
+        .map(warp::reply::Reply::into_response);
+
+    let readyz = warp::path("readyz")
+        .map(|| warp::reply::with_status("ok", warp::http::StatusCode::OK))
+        .map(warp::reply::Reply::into_response);
+
+    let metrics = warp::path("metrics")
+        .map(move || {
+            let encoder = prometheus::TextEncoder::new();
+            let metric_families = registry.gather();
+            let mut buffer = Vec::new();
+            let _ = encoder.encode(&metric_families, &mut buffer);
+
+            warp::reply::with_header(buffer, "content-type", encoder.format_type().to_owned())
+        })
+        .map(warp::reply::Reply::into_response);
+
+    healthz.or(readyz).unify().or(metrics).unify()
+}
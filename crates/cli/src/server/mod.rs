@@ -0,0 +1,410 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod listener;
+mod metrics;
+mod tls;
+mod watch;
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use clap::Parser;
+use futures::stream::StreamExt;
+use hyper::{header, server::accept, Server, Version};
+use mas_config::RootConfig;
+use mas_email::{MailTransport, Mailer};
+use mas_storage::MIGRATOR;
+use mas_tasks::TaskQueue;
+use mas_templates::Templates;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry_http::HeaderExtractor;
+use tower::{make::Shared, ServiceBuilder};
+use tower_http::{
+    compression::CompressionLayer,
+    sensitive_headers::SetSensitiveHeadersLayer,
+    trace::{MakeSpan, OnResponse, TraceLayer},
+};
+use tracing::{error, field, info};
+
+use self::{
+    listener::{bind_listener, BoxedIncoming, Connection, Listener},
+    metrics::{admin_routes, HttpMetrics, MetricsLayer, DEFAULT_ADMIN_ADDRESS},
+    tls::build_tls_acceptor,
+    watch::{watch_config, watch_templates},
+};
+use super::RootCommand;
+
+/// Default per-request timeout, used unless overridden by `config.http`.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default HTTP/1 header-read timeout, used unless overridden by
+/// `config.http`.
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser, Debug, Default)]
+pub(super) struct ServerCommand {
+    /// Automatically apply pending migrations
+    #[clap(long)]
+    migrate: bool,
+
+    /// Watch for changes to templates, the TLS certificate and the config file
+    /// on the filesystem
+    #[clap(short, long)]
+    watch: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct OtelMakeSpan;
+
+impl<B> MakeSpan<B> for OtelMakeSpan {
+    fn make_span(&mut self, request: &hyper::Request<B>) -> tracing::Span {
+        // Extract the context from the headers
+        let headers = request.headers();
+        let extractor = HeaderExtractor(headers);
+
+        let cx = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&extractor)
+        });
+
+        let cx = if cx.span().span_context().is_remote() {
+            cx
+        } else {
+            opentelemetry::Context::new()
+        };
+
+        // Attach the context so when the request span is created it gets properly
+        // parented
+        let _guard = cx.attach();
+
+        let version = match request.version() {
+            Version::HTTP_09 => "0.9",
+            Version::HTTP_10 => "1.0",
+            Version::HTTP_11 => "1.1",
+            Version::HTTP_2 => "2.0",
+            Version::HTTP_3 => "3.0",
+            _ => "",
+        };
+
+        let span = tracing::info_span!(
+            "request",
+            http.method = %request.method(),
+            http.target = %request.uri(),
+            http.flavor = version,
+            http.status_code = field::Empty,
+            http.user_agent = field::Empty,
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+        );
+
+        if let Some(user_agent) = headers
+            .get(header::USER_AGENT)
+            .and_then(|s| s.to_str().ok())
+        {
+            span.record("http.user_agent", &user_agent);
+        }
+
+        span
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct OtelOnResponse;
+
+impl<B> OnResponse<B> for OtelOnResponse {
+    fn on_response(self, response: &hyper::Response<B>, _latency: Duration, span: &tracing::Span) {
+        let s = response.status();
+        let status = if s.is_success() {
+            "ok"
+        } else if s.is_client_error() || s.is_server_error() {
+            "error"
+        } else {
+            "unset"
+        };
+        span.record("otel.status_code", &status);
+        span.record("http.status_code", &s.as_u16());
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    // Wait for the CTRL+C signal
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install Ctrl+C signal handler");
+
+    tracing::info!("Got Ctrl+C, shutting down");
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // Wait for SIGTERM and SIGINT signals
+    // This might panic but should be fine
+    let mut term =
+        signal(SignalKind::terminate()).expect("failed to install SIGTERM signal handler");
+    let mut int = signal(SignalKind::interrupt()).expect("failed to install SIGINT signal handler");
+
+    tokio::select! {
+        _ = term.recv() => tracing::info!("Got SIGTERM, shutting down"),
+        _ = int.recv() => tracing::info!("Got SIGINT, shutting down"),
+    };
+}
+
+impl ServerCommand {
+    pub async fn run(&self, root: &RootCommand) -> anyhow::Result<()> {
+        let config: RootConfig = root.load_config()?;
+        // Wrap the config in an `ArcSwap` so a `--watch`ed reload can be picked
+        // up by the parts of the server that support it without a restart
+        let config = Arc::new(ArcSwap::from_pointee(config));
+        let cfg = config.load();
+
+        let listener = bind_listener(&cfg.http.address, cfg.http.unix_socket_mode.as_deref())?;
+
+        // Warm up and validate the TLS certificate now, so that a bad cert fails
+        // fast at startup instead of at the first handshake.
+        let tls = cfg
+            .http
+            .tls
+            .as_ref()
+            .map(build_tls_acceptor)
+            .transpose()
+            .context("could not set up TLS")?;
+
+        // Connect to the mail server
+        let mail_transport = MailTransport::from_config(&cfg.email.transport).await?;
+        mail_transport.test_connection().await?;
+
+        // Connect to the database
+        let pool = cfg.database.connect().await?;
+
+        if self.migrate {
+            info!("Running pending migrations");
+            MIGRATOR
+                .run(&pool)
+                .await
+                .context("could not run migrations")?;
+        }
+
+        info!("Starting task scheduler");
+        let queue = TaskQueue::default();
+        queue.recuring(Duration::from_secs(15), mas_tasks::cleanup_expired(&pool));
+        queue.start();
+
+        // Initialize the key store
+        let key_store = cfg
+            .oauth2
+            .key_store()
+            .await
+            .context("could not import keys from config")?;
+        // Wrap the key store in an `ArcSwap` so it can be hot-reloaded without
+        // request handlers having to take a lock
+        let key_store = Arc::new(ArcSwap::from_pointee(key_store));
+
+        // Load and compile the templates
+        let templates = Templates::load_from_config(&cfg.templates)
+            .await
+            .context("could not load templates")?;
+
+        let mailer = Mailer::new(
+            &templates,
+            &mail_transport,
+            &cfg.email.from,
+            &cfg.email.reply_to,
+        );
+        let mailer = Arc::new(ArcSwap::from_pointee(mailer));
+
+        // Wrap the mail transport in an `ArcSwap` so the admin listener's
+        // health check keeps probing the same transport a config reload
+        // swaps into the mailer, rather than a stale one captured at startup
+        let mail_transport = Arc::new(ArcSwap::from_pointee(mail_transport));
+
+        drop(cfg);
+
+        // Watch for changes in templates, the TLS certificate and the config
+        // file itself (if configured) if the --watch flag is present
+        if self.watch {
+            let client = watchman_client::Connector::new()
+                .connect()
+                .await
+                .context("could not connect to watchman")?;
+
+            watch_templates(&client, &templates)
+                .await
+                .context("could not watch for templates changes")?;
+
+            if let (Some(tls_config), Some((_, resolver))) = (&config.load().http.tls, &tls) {
+                tls::watch_tls(&client, tls_config, resolver.clone())
+                    .await
+                    .context("could not watch for TLS certificate changes")?;
+            }
+
+            watch_config(
+                &client,
+                root.clone(),
+                templates.clone(),
+                config.clone(),
+                mailer.clone(),
+                mail_transport.clone(),
+                key_store.clone(),
+            )
+            .await
+            .context("could not watch for config file changes")?;
+        }
+
+        // Start the server
+        //
+        // mas_handlers::root() takes plain references, not the ArcSwaps we use
+        // to hot-reload config/mailer/key_store elsewhere, so take a snapshot
+        // of each right before building the routes rather than changing its
+        // signature. Handlers built from a given snapshot keep using it until
+        // the process restarts; only the admin listener's health check, which
+        // loads the mail transport on every use, sees a config change applied
+        // live. The TLS resolver is swapped separately, on certificate file
+        // changes.
+        let root_filter = mas_handlers::root(
+            &pool,
+            &templates,
+            &**key_store.load(),
+            &**mailer.load(),
+            &**config.load(),
+        );
+
+        let warp_service = warp::service(root_filter);
+
+        let cfg = config.load();
+
+        // Set up the Prometheus registry backing both the `/metrics` endpoint
+        // and the request metrics recorded on the main listener
+        let prometheus_registry = prometheus::Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(prometheus_registry.clone())
+            .build()
+            .context("could not build the Prometheus exporter")?;
+        opentelemetry::global::set_meter_provider(exporter);
+        let meter = opentelemetry::global::meter("mas_http");
+        let http_metrics = Arc::new(HttpMetrics::new(&meter));
+
+        // Serve the admin/metrics listener on its own, independently-bound
+        // address, defaulting to loopback-only
+        let admin_addr: SocketAddr = cfg
+            .admin
+            .address
+            .as_deref()
+            .unwrap_or(DEFAULT_ADMIN_ADDRESS)
+            .parse()
+            .context("could not parse admin listener address")?;
+        let admin_listener = TcpListener::bind(admin_addr).context("could not bind admin address")?;
+        info!("Listening on http://{} for admin/metrics", admin_addr);
+        let admin_service = warp::service(admin_routes(
+            pool.clone(),
+            mail_transport.clone(),
+            prometheus_registry,
+        ));
+        tokio::spawn(
+            Server::from_tcp(admin_listener)?
+                .serve(Shared::new(admin_service))
+                .with_graceful_shutdown(shutdown_signal()),
+        );
+
+        let service = ServiceBuilder::new()
+            // Add high level tracing/logging to all requests
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(OtelMakeSpan)
+                    .on_response(OtelOnResponse),
+            )
+            // Record request counts, an in-flight gauge and latency histograms
+            .layer(MetricsLayer {
+                metrics: http_metrics.clone(),
+            })
+            // Set a timeout
+            .timeout(cfg.http.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT))
+            // Compress responses
+            .layer(CompressionLayer::new())
+            // Mark the `Authorization` and `Cookie` headers as sensitive so it doesn't show in logs
+            .layer(SetSensitiveHeadersLayer::new(vec![
+                header::AUTHORIZATION,
+                header::COOKIE,
+            ]))
+            .service(warp_service);
+
+        // Remember the unix socket path, if any, so we can clean it up on exit
+        let socket_path = listener.socket_path();
+
+        let incoming = listener.into_incoming()?;
+
+        let incoming: BoxedIncoming = match tls {
+            Some((acceptor, _)) => {
+                let stream = incoming.then(move |conn| {
+                    let acceptor = acceptor.clone();
+                    async move {
+                        let conn = conn?;
+                        let conn = acceptor.accept(conn).await?;
+                        Ok(Box::new(conn) as Box<dyn Connection>)
+                    }
+                });
+                Box::pin(stream)
+            }
+            None => incoming,
+        };
+
+        let server = Server::builder(accept::from_stream(incoming))
+            .http1_keepalive(cfg.http.http1_keep_alive.unwrap_or(true))
+            .http1_header_read_timeout(
+                cfg.http
+                    .http1_header_read_timeout
+                    .unwrap_or(DEFAULT_HEADER_READ_TIMEOUT),
+            )
+            .serve(Shared::new(service))
+            .with_graceful_shutdown(shutdown_signal());
+
+        let result = match cfg.http.drain_timeout {
+            Some(drain_timeout) => {
+                tokio::select! {
+                    result = server => result,
+                    () = async {
+                        shutdown_signal().await;
+                        tokio::time::sleep(drain_timeout).await;
+                    } => {
+                        error!(
+                            ?drain_timeout,
+                            abandoned_connections = http_metrics.in_flight(),
+                            "Graceful shutdown drain timeout elapsed, forcing exit with requests still in flight"
+                        );
+                        Ok(())
+                    }
+                }
+            }
+            None => server.await,
+        };
+
+        // Clean up the socket file, whether we're shutting down cleanly or not
+        if let Some(path) = socket_path {
+            if let Err(err) = std::fs::remove_file(&path) {
+                error!(%err, path = %path.display(), "Could not remove unix socket file");
+            }
+        }
+
+        result?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS termination for the main listener, with hot-reloadable certificates
+//! and optional mTLS.
+
+use std::{io, path::Path, sync::Arc};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use futures::{future::TryFutureExt, stream::TryStreamExt};
+use rustls::{server::ResolvesServerCert, sign::CertifiedKey, ServerConfig as RustlsServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+/// Resolves the TLS certificate to present to clients out of an
+/// [`ArcSwap`], so that it can be hot-swapped without dropping connections.
+pub(super) struct CertResolver(pub(super) ArcSwap<CertifiedKey>);
+
+impl std::fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertResolver").finish()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: rustls::server::ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.load_full())
+    }
+}
+
+/// Load a certificate chain and private key from disk and turn them into a
+/// [`CertifiedKey`] rustls can present to clients.
+fn load_certified_key(certificate: &Path, key: &Path) -> anyhow::Result<CertifiedKey> {
+    let certs = {
+        let file = std::fs::File::open(certificate)
+            .with_context(|| format!("could not open {}", certificate.display()))?;
+        let mut reader = io::BufReader::new(file);
+        rustls_pemfile::certs(&mut reader)
+            .context("could not parse certificate chain")?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect()
+    };
+
+    let key = {
+        let file = std::fs::File::open(key)
+            .with_context(|| format!("could not open {}", key.display()))?;
+        let mut reader = io::BufReader::new(file);
+        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut reader)
+            .context("could not parse private key")?;
+        let key = keys.pop().context("no private key found")?;
+        rustls::PrivateKey(key)
+    };
+
+    let signing_key =
+        rustls::sign::any_supported_type(&key).context("unsupported private key type")?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Build a [`TlsAcceptor`] out of the `tls` config section, along with a
+/// handle to the cert resolver it uses, so it can be hot-reloaded later.
+pub(super) fn build_tls_acceptor(
+    tls: &mas_config::TlsConfig,
+) -> anyhow::Result<(TlsAcceptor, Arc<CertResolver>)> {
+    let certified_key = load_certified_key(&tls.certificate, &tls.key)
+        .context("could not load TLS certificate, refusing to start")?;
+
+    let resolver = Arc::new(CertResolver(ArcSwap::from_pointee(certified_key)));
+
+    let builder = RustlsServerConfig::builder().with_safe_defaults();
+
+    let mut server_config = if let Some(client_ca) = &tls.client_ca {
+        let verifier = build_client_cert_verifier(client_ca)
+            .context("could not set up client certificate verification")?;
+        builder
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(resolver.clone())
+    } else {
+        builder
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone())
+    };
+
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok((TlsAcceptor::from(Arc::new(server_config)), resolver))
+}
+
+/// Build a client certificate verifier out of a PEM-encoded CA bundle, for
+/// optional mTLS: clients are required to present a certificate signed by
+/// one of these CAs to complete the handshake.
+fn build_client_cert_verifier(
+    client_ca: &Path,
+) -> anyhow::Result<Arc<dyn rustls::server::ClientCertVerifier>> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    let file = std::fs::File::open(client_ca)
+        .with_context(|| format!("could not open {}", client_ca.display()))?;
+    let mut reader = io::BufReader::new(file);
+    for cert in rustls_pemfile::certs(&mut reader).context("could not parse client CA bundle")? {
+        roots
+            .add(&rustls::Certificate(cert))
+            .context("could not add client CA certificate")?;
+    }
+
+    Ok(rustls::server::AllowAnyAuthenticatedClient::new(roots))
+}
+
+/// Watch the directory holding the TLS certificate for changes, and hot-swap
+/// the resolver's [`CertifiedKey`] whenever a file in it gets rewritten. Only
+/// that directory is watched: a key kept in another directory is reloaded
+/// with the certificate, but a change to the key alone is not noticed.
+pub(super) async fn watch_tls(
+    client: &watchman_client::Client,
+    tls: &mas_config::TlsConfig,
+    resolver: Arc<CertResolver>,
+) -> anyhow::Result<()> {
+    use watchman_client::{
+        fields::NameOnly,
+        pdu::{QueryResult, SubscribeRequest},
+        CanonicalPath, SubscriptionData,
+    };
+
+    let certificate = tls.certificate.clone();
+    let key = tls.key.clone();
+
+    let root = certificate
+        .parent()
+        .context("certificate path has no parent directory")?;
+    let resolved = client
+        .resolve_root(CanonicalPath::canonicalize(root)?)
+        .await?;
+
+    let (subscription, _) = client
+        .subscribe::<NameOnly>(&resolved, SubscribeRequest::default())
+        .await?;
+
+    let stream = futures::stream::try_unfold(subscription, |mut sub| async move {
+        let next = sub.next().await?;
+        anyhow::Ok(Some((next, sub)))
+    });
+
+    let fut = stream
+        .try_filter_map(|event| async move {
+            match event {
+                SubscriptionData::FilesChanged(QueryResult {
+                    files: Some(files), ..
+                }) => Ok(Some(files)),
+                _ => Ok(None),
+            }
+        })
+        .try_for_each(move |files| {
+            let resolver = resolver.clone();
+            let certificate = certificate.clone();
+            let key = key.clone();
+            async move {
+                info!(?files, "Certificate files changed, reloading");
+
+                match load_certified_key(&certificate, &key) {
+                    Ok(certified_key) => resolver.0.store(Arc::new(certified_key)),
+                    Err(err) => error!(%err, "Could not reload TLS certificate, keeping the previous one"),
+                }
+
+                anyhow::Ok(())
+            }
+        })
+        .inspect_err(|err| error!(%err, "Error while watching TLS certificate, stop watching"));
+
+    tokio::spawn(fut);
+
+    Ok(())
+}
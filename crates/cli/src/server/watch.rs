@@ -0,0 +1,265 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watching the filesystem for changes to templates and the config file,
+//! and hot-applying them without a restart.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use futures::{future::TryFutureExt, stream::TryStreamExt};
+use mas_config::RootConfig;
+use mas_email::{MailTransport, Mailer};
+use mas_templates::Templates;
+use tracing::{error, info, warn};
+
+use super::super::RootCommand;
+
+/// Filename extensions the templates loader cares about, so we only
+/// subscribe to the events that could actually require a reload.
+const TEMPLATE_EXTENSIONS: &[&str] = &["html", "txt", "subject"];
+
+/// How long to wait after the last file-change event before reloading, so a
+/// bulk `git checkout` or editor save-all triggers a single recompile rather
+/// than a storm of them.
+const TEMPLATES_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watch for changes in the templates folders
+pub(super) async fn watch_templates(
+    client: &watchman_client::Client,
+    templates: &Templates,
+) -> anyhow::Result<()> {
+    use watchman_client::{
+        expr::Expr,
+        fields::NameOnly,
+        pdu::{QueryResult, SubscribeRequest},
+        CanonicalPath, SubscriptionData,
+    };
+
+    let templates = templates.clone();
+
+    // Only wake up for the file extensions the templates loader actually cares
+    // about
+    let expression = Expr::Any(
+        TEMPLATE_EXTENSIONS
+            .iter()
+            .map(|ext| Expr::Suffix((*ext).to_owned()))
+            .collect(),
+    );
+
+    // Find which roots we're supposed to watch
+    let roots = templates.watch_roots().await;
+    let mut streams = Vec::new();
+
+    for root in roots {
+        // For each root, create a subscription
+        let resolved = client
+            .resolve_root(CanonicalPath::canonicalize(root)?)
+            .await?;
+
+        let request = SubscribeRequest {
+            expression: Some(expression.clone()),
+            ..Default::default()
+        };
+        let (subscription, _) = client.subscribe::<NameOnly>(&resolved, request).await?;
+
+        // Create a stream out of that subscription
+        let stream = futures::stream::try_unfold(subscription, |mut sub| async move {
+            let next = sub.next().await?;
+            anyhow::Ok(Some((next, sub)))
+        });
+
+        streams.push(Box::pin(stream));
+    }
+
+    let mut files_changed_stream =
+        futures::stream::select_all(streams).try_filter_map(|event| async move {
+            match event {
+                SubscriptionData::FilesChanged(QueryResult {
+                    files: Some(files), ..
+                }) => {
+                    let files: Vec<_> = files.into_iter().map(|f| f.name.into_inner()).collect();
+                    Ok(Some(files))
+                }
+                _ => Ok(None),
+            }
+        });
+
+    // Debounce the incoming events: wait for a quiet period before reloading,
+    // coalescing bursts of changes into a single reload
+    let fut = async move {
+        let mut pending: Option<Vec<String>> = None;
+        let sleep = tokio::time::sleep(TEMPLATES_DEBOUNCE);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                item = files_changed_stream.try_next() => {
+                    match item? {
+                        Some(files) => {
+                            pending.get_or_insert_with(Vec::new).extend(files);
+                            sleep.as_mut().reset(tokio::time::Instant::now() + TEMPLATES_DEBOUNCE);
+                        }
+                        None => break,
+                    }
+                }
+
+                () = &mut sleep, if pending.is_some() => {
+                    let files = pending.take().expect("pending is Some");
+                    info!(?files, "Files changed, reloading templates");
+
+                    templates
+                        .clone()
+                        .reload()
+                        .await
+                        .context("Could not reload templates")?;
+                }
+            }
+        }
+
+        anyhow::Ok(())
+    }
+    .inspect_err(|err| error!(%err, "Error while watching templates, stop watching"));
+
+    tokio::spawn(fut);
+
+    Ok(())
+}
+
+/// Watch the directory containing the config file for changes, and reload
+/// whatever part of it can be safely swapped in -- the mailer with its mail
+/// transport, and the OAuth2 key store -- then store the new config as a
+/// whole. OAuth2 client registrations are not refreshed separately. Settings
+/// that can't be applied in place, like the listener address or the database
+/// DSN, are logged as requiring a restart instead.
+///
+/// Reloading here only updates the shared `ArcSwap`s, not every consumer:
+/// the admin listener's `/healthz` loads a fresh mail transport snapshot on
+/// every use, so that genuinely applies without a restart. The TLS
+/// certificate resolver is not touched here.
+/// The main listener's request handlers are built once from a snapshot of
+/// `config`/`mailer`/`key_store` at startup (see the `mas_handlers::root`
+/// call site in `mod.rs`), so a reload here -- including OAuth2 key
+/// rotation -- is not picked up by them until the process is restarted.
+pub(super) async fn watch_config<K>(
+    client: &watchman_client::Client,
+    root: RootCommand,
+    templates: Templates,
+    config: Arc<ArcSwap<RootConfig>>,
+    mailer: Arc<ArcSwap<Mailer>>,
+    mail_transport: Arc<ArcSwap<MailTransport>>,
+    key_store: Arc<ArcSwap<K>>,
+) -> anyhow::Result<()>
+where
+    K: Send + Sync + 'static,
+{
+    use watchman_client::{
+        fields::NameOnly,
+        pdu::{QueryResult, SubscribeRequest},
+        CanonicalPath, SubscriptionData,
+    };
+
+    let config_path = root.config_path();
+    let watch_root = config_path
+        .parent()
+        .context("config path has no parent directory")?;
+    let resolved = client
+        .resolve_root(CanonicalPath::canonicalize(watch_root)?)
+        .await?;
+
+    let (subscription, _) = client
+        .subscribe::<NameOnly>(&resolved, SubscribeRequest::default())
+        .await?;
+
+    let stream = futures::stream::try_unfold(subscription, |mut sub| async move {
+        let next = sub.next().await?;
+        anyhow::Ok(Some((next, sub)))
+    });
+
+    let fut = stream
+        .try_filter_map(|event| async move {
+            match event {
+                SubscriptionData::FilesChanged(QueryResult {
+                    files: Some(files), ..
+                }) => Ok(Some(files)),
+                _ => Ok(None),
+            }
+        })
+        .try_for_each(move |files| {
+            let root = root.clone();
+            let templates = templates.clone();
+            let config = config.clone();
+            let mailer = mailer.clone();
+            let mail_transport = mail_transport.clone();
+            let key_store = key_store.clone();
+            async move {
+                info!(?files, "Config file changed, reloading");
+
+                let new_config = match root.load_config() {
+                    Ok(new_config) => new_config,
+                    Err(err) => {
+                        error!(%err, "Could not reload config, keeping the previous one");
+                        return anyhow::Ok(());
+                    }
+                };
+
+                {
+                    let running = config.load();
+                    if running.http.address != new_config.http.address {
+                        warn!("Listener address changed in config: restart required to apply");
+                    }
+                    if running.database.uri != new_config.database.uri {
+                        warn!("Database connection changed in config: restart required to apply");
+                    }
+                }
+
+                // Rebuild the mailer against a freshly-connected mail transport, and
+                // swap the transport used by the admin listener's health check too,
+                // so it doesn't keep probing a stale one after this reload
+                match MailTransport::from_config(&new_config.email.transport).await {
+                    Ok(new_transport) => {
+                        let new_mailer = Mailer::new(
+                            &templates,
+                            &new_transport,
+                            &new_config.email.from,
+                            &new_config.email.reply_to,
+                        );
+                        mailer.store(Arc::new(new_mailer));
+                        mail_transport.store(Arc::new(new_transport));
+                    }
+                    Err(err) => {
+                        error!(%err, "Could not apply new mail transport, keeping the previous one");
+                    }
+                }
+
+                // Re-import the OAuth2 keys
+                match new_config.oauth2.key_store().await {
+                    Ok(new_key_store) => key_store.store(Arc::new(new_key_store)),
+                    Err(err) => {
+                        error!(%err, "Could not reload OAuth2 key store, keeping the previous one");
+                    }
+                }
+
+                config.store(Arc::new(new_config));
+
+                anyhow::Ok(())
+            }
+        })
+        .inspect_err(|err| error!(%err, "Error while watching config file, stop watching"));
+
+    tokio::spawn(fut);
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Context;
+
+/// Database connection settings.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct DatabaseConfig {
+    /// Postgres connection URI.
+    pub uri: String,
+}
+
+impl DatabaseConfig {
+    /// Connect to the database, returning a ready-to-use connection pool.
+    pub async fn connect(&self) -> anyhow::Result<sqlx::PgPool> {
+        sqlx::PgPool::connect(&self.uri)
+            .await
+            .context("could not connect to the database")
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Outgoing mail settings.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct EmailConfig {
+    /// Settings for the transport used to actually send mail, handed to
+    /// [`mas_email::MailTransport::from_config`].
+    pub transport: mas_email::TransportConfig,
+
+    /// The `From:` address used on outgoing mail.
+    pub from: String,
+
+    /// The `Reply-To:` address used on outgoing mail.
+    pub reply_to: String,
+}
@@ -0,0 +1,54 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use crate::TlsConfig;
+
+/// Configuration for the main HTTP(S) listener.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct HttpConfig {
+    /// `host:port` to listen on, or a `unix:`-prefixed path to bind a Unix
+    /// domain socket instead.
+    pub address: String,
+
+    /// Permissions to apply to a freshly-bound Unix domain socket, as an
+    /// octal string (e.g. `"0660"`). Only used when `address` is a `unix:`
+    /// path. Defaults to `0660`, restricting access to the owner and group.
+    #[serde(default)]
+    pub unix_socket_mode: Option<String>,
+
+    /// TLS termination settings. When unset, the listener serves plain HTTP.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    /// How long to keep draining in-flight requests after a shutdown signal
+    /// before forcing the process to exit. When unset, shutdown waits
+    /// indefinitely for in-flight requests to finish.
+    #[serde(default, with = "humantime_serde::option")]
+    pub drain_timeout: Option<Duration>,
+
+    /// Whether to enable HTTP/1 keep-alive. Defaults to enabled.
+    #[serde(default)]
+    pub http1_keep_alive: Option<bool>,
+
+    /// How long to wait for a client to finish sending request headers
+    /// before timing out the connection.
+    #[serde(default, with = "humantime_serde::option")]
+    pub http1_header_read_timeout: Option<Duration>,
+
+    /// Per-request timeout applied to the whole service stack.
+    #[serde(default, with = "humantime_serde::option")]
+    pub request_timeout: Option<Duration>,
+}
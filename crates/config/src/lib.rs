@@ -0,0 +1,42 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Schema for the application's YAML config file.
+
+mod admin;
+mod database;
+mod email;
+mod http;
+mod oauth2;
+mod tls;
+
+pub use self::{
+    admin::AdminConfig, database::DatabaseConfig, email::EmailConfig, http::HttpConfig,
+    oauth2::OAuth2Config, tls::TlsConfig,
+};
+
+/// Top-level application configuration, loaded from the YAML config file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RootConfig {
+    pub http: HttpConfig,
+
+    /// Configuration for the separate admin/metrics listener.
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    pub database: DatabaseConfig,
+    pub email: EmailConfig,
+    pub oauth2: OAuth2Config,
+    pub templates: mas_templates::TemplatesConfig,
+}
@@ -0,0 +1,32 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Context;
+
+/// OAuth2 issuer settings, including the signing keys to import at startup.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct OAuth2Config {
+    /// Signing keys to import into the key store, in the format expected by
+    /// [`mas_oauth2::KeyStore::import`].
+    pub keys: Vec<mas_oauth2::KeySource>,
+}
+
+impl OAuth2Config {
+    /// Import the configured keys into a fresh [`mas_oauth2::KeyStore`].
+    pub async fn key_store(&self) -> anyhow::Result<mas_oauth2::KeyStore> {
+        mas_oauth2::KeyStore::import(&self.keys)
+            .await
+            .context("could not import OAuth2 signing keys")
+    }
+}
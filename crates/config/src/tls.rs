@@ -0,0 +1,31 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+
+/// TLS termination settings for the main HTTP(S) listener.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TlsConfig {
+    /// Path to the PEM-encoded certificate chain to present to clients.
+    pub certificate: PathBuf,
+
+    /// Path to the PEM-encoded PKCS#8 private key matching `certificate`.
+    pub key: PathBuf,
+
+    /// Path to a PEM-encoded CA bundle used to verify client certificates.
+    /// When set, clients are required to present a certificate signed by one
+    /// of these CAs; when unset, client certificates are not requested.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}